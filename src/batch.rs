@@ -0,0 +1,478 @@
+//! Generic request-batching worker.
+//!
+//! [`Batch`] is a reusable building block for the "collect many requests, flush
+//! them upstream as one call" pattern used by the embedding proxy. It owns a
+//! background task that accumulates items from an `mpsc` channel until either
+//! `max_items` have arrived or `max_latency` has elapsed since the *first* item
+//! of the current batch, then hands the whole `Vec` to a [`BatchService`] in one
+//! call and fans the result back out to each waiting caller.
+//!
+//! Keeping this generic over the request/response/error types lets the
+//! embedding proxy become just one instantiation, and lets the batching policy
+//! itself be exercised in isolation from HTTP and `reqwest`.
+//!
+//! Each flush runs under a `batch_flush` [`tracing`] span recording the batch
+//! size, why it closed (`size` vs. `timeout` vs. `channel_closed`), the
+//! round-trip duration of the wrapped [`BatchService`] call, and (once the
+//! service records it) an `upstream_status` field. The span
+//! of every request folded into that batch is linked to it via
+//! [`tracing::Span::follows_from`], so callers can trace a single request from
+//! submission through whichever batch it ended up in.
+//!
+//! Each flush also records how long every one of its messages sat waiting
+//! since [`Batch::try_submit`] onto a `queue_wait_ms` field on that message's
+//! own span, so a caller's span can carry its own queueing latency distinct
+//! from the flush span's upstream round-trip `duration_ms`.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+use tokio::sync::{mpsc, oneshot};
+use tracing::Instrument;
+
+/// Channel capacity for the internal job queue between [`Batch::try_submit`]
+/// callers and the worker task. Once full, new requests are rejected rather
+/// than queued, so the worker can never fall arbitrarily far behind.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// A boxed `Send` future, used so [`BatchService`] implementors can be plain
+/// structs without pulling in an `async_trait` dependency.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Something that knows how to process a whole batch of requests at once,
+/// e.g. by making a single upstream HTTP call with all of them.
+pub trait BatchService<Req> {
+    type Response;
+    type Error;
+
+    /// Process `batch` and return one response per request, in the same order.
+    fn call(&self, batch: Vec<Req>) -> BoxFuture<'_, Result<Vec<Self::Response>, Self::Error>>;
+}
+
+/// A queued request plus the channel its caller is waiting on.
+struct Message<Req, Res, Err> {
+    payload: Req,
+    reply: oneshot::Sender<Result<Res, Err>>,
+    /// The submitting request's span, so the batch it lands in can be linked
+    /// back to it with [`tracing::Span::follows_from`] regardless of which
+    /// other requests it ends up sharing a batch with.
+    caller_span: tracing::Span,
+    /// When [`Batch::try_submit`] queued this message, so the flush can
+    /// record how long it sat waiting (in the channel, then filling out its
+    /// batch) before the upstream call it's part of even started.
+    queued_at: Instant,
+}
+
+/// A pending reply from a submitted request. Awaiting it resolves once the
+/// batch it landed in has been flushed and a result is available.
+pub struct Pending<Res, Err> {
+    receiver: oneshot::Receiver<Result<Res, Err>>,
+}
+
+impl<Res, Err> Pending<Res, Err> {
+    pub async fn wait(self) -> Result<Res, BatchError<Err>> {
+        match self.receiver.await {
+            Ok(result) => result.map_err(BatchError::Upstream),
+            Err(_) => Err(BatchError::QueueClosed),
+        }
+    }
+}
+
+/// Error waiting on an already-queued [`Pending`] request.
+#[derive(Debug)]
+pub enum BatchError<Err> {
+    /// The reply was dropped without an answer: the worker task stopped, or
+    /// the wrapped [`BatchService`] returned fewer responses than the batch
+    /// had requests and this request was one of those left without one.
+    QueueClosed,
+    /// The wrapped [`BatchService`] failed to process the batch.
+    Upstream(Err),
+}
+
+/// Error queuing a new request via [`Batch::try_submit`]: the queue is either
+/// full (the caller should back off and retry) or closed (the worker has
+/// stopped for good).
+#[derive(Debug, Clone, Copy)]
+pub enum SubmitError {
+    /// The queue is at capacity.
+    Full,
+    /// The worker task is no longer accepting requests.
+    Closed,
+}
+
+/// Unifies a failure to queue a request with a failure of the batch it
+/// eventually joined, so callers can handle both kinds of failure the request
+/// can run into with a single `match`.
+#[derive(Debug)]
+pub enum CallError<Err> {
+    /// The queue is at capacity.
+    Full,
+    /// The worker task is no longer accepting requests.
+    Closed,
+    /// The wrapped [`BatchService`] failed to process the batch.
+    Upstream(Err),
+}
+
+impl<Err> From<SubmitError> for CallError<Err> {
+    fn from(err: SubmitError) -> Self {
+        match err {
+            SubmitError::Full => CallError::Full,
+            SubmitError::Closed => CallError::Closed,
+        }
+    }
+}
+
+impl<Err> From<BatchError<Err>> for CallError<Err> {
+    fn from(err: BatchError<Err>) -> Self {
+        match err {
+            BatchError::QueueClosed => CallError::Closed,
+            BatchError::Upstream(err) => CallError::Upstream(err),
+        }
+    }
+}
+
+/// A handle to a running batching worker. Cloning shares the same worker and
+/// queue; dropping the last handle closes the queue and lets the worker drain
+/// its final batch and exit.
+#[derive(Clone)]
+pub struct Batch<Req, Res, Err> {
+    sender: mpsc::Sender<Message<Req, Res, Err>>,
+}
+
+impl<Req, Res, Err> Batch<Req, Res, Err>
+where
+    Req: Send + 'static,
+    Res: Send + 'static,
+    Err: Clone + Send + 'static,
+{
+    /// Spawn a worker around `service` that flushes a batch once it holds
+    /// `max_items` requests, or once `max_latency` has passed since the first
+    /// request of the batch arrived, whichever comes first.
+    ///
+    /// Returns the handle alongside a [`JoinHandle`](tokio::task::JoinHandle)
+    /// for the worker task. For a graceful shutdown, drop every clone of the
+    /// handle (closing the queue) and then await the join handle: the worker
+    /// will flush whatever partial batch it is holding, wait for that flush's
+    /// upstream call to finish, and deliver every pending reply before its
+    /// task exits, so no caller is left holding a [`Pending`] that never
+    /// resolves.
+    pub fn new<S>(service: S, max_items: usize, max_latency: Duration) -> (Self, tokio::task::JoinHandle<()>)
+    where
+        S: BatchService<Req, Response = Res, Error = Err> + Send + Sync + 'static,
+    {
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        let worker = tokio::spawn(Self::worker(service, receiver, max_items, max_latency));
+        (Batch { sender }, worker)
+    }
+
+    /// Queue `payload` and return a handle to its eventual result without
+    /// waiting for it, so multiple requests can be submitted before any of
+    /// them are awaited (letting them land in the same batch).
+    ///
+    /// This never blocks: a full queue is reported as [`SubmitError::Full`]
+    /// immediately rather than making the caller wait for room, so callers
+    /// under load can turn it into explicit backpressure (e.g. an HTTP 503)
+    /// instead of silently piling up.
+    pub fn try_submit(&self, payload: Req) -> Result<Pending<Res, Err>, SubmitError> {
+        let (reply, receiver) = oneshot::channel();
+        let caller_span = tracing::Span::current();
+        let queued_at = Instant::now();
+        match self.sender.try_send(Message { payload, reply, caller_span, queued_at }) {
+            Ok(()) => Ok(Pending { receiver }),
+            Err(mpsc::error::TrySendError::Full(_)) => Err(SubmitError::Full),
+            Err(mpsc::error::TrySendError::Closed(_)) => Err(SubmitError::Closed),
+        }
+    }
+
+    /// Queue `payload` and wait for its result.
+    pub async fn call(&self, payload: Req) -> Result<Res, CallError<Err>> {
+        let pending = self.try_submit(payload)?;
+        Ok(pending.wait().await?)
+    }
+
+    async fn worker<S>(
+        service: S,
+        mut receiver: mpsc::Receiver<Message<Req, Res, Err>>,
+        max_items: usize,
+        max_latency: Duration,
+    ) where
+        S: BatchService<Req, Response = Res, Error = Err> + Send + Sync + 'static,
+    {
+        loop {
+            // Wait indefinitely for the first item of a new batch: starting the
+            // flush timer here (rather than unconditionally at the top of this
+            // loop) means idle time between batches never eats into the latency
+            // budget of the next one.
+            let first = match receiver.recv().await {
+                Some(message) => message,
+                None => break,
+            };
+            let mut batch = vec![first];
+            let alarm_clock = tokio::time::sleep(max_latency);
+            tokio::pin!(alarm_clock);
+            // Assume the batch filled up; overwritten below if it closed some
+            // other way, so the flush span can say which one actually happened.
+            let mut closed_by = "size";
+            while batch.len() < max_items {
+                tokio::select! {
+                    received = receiver.recv() => {
+                        match received {
+                            Some(message) => batch.push(message),
+                            None => {
+                                closed_by = "channel_closed";
+                                break;
+                            }
+                        }
+                    }
+                    _ = &mut alarm_clock => {
+                        closed_by = "timeout";
+                        tracing::debug!("Batch timed out");
+                        break;
+                    }
+                }
+            }
+            Self::flush(&service, batch, closed_by).await;
+        }
+    }
+
+    async fn flush<S>(service: &S, batch: Vec<Message<Req, Res, Err>>, closed_by: &'static str)
+    where
+        S: BatchService<Req, Response = Res, Error = Err> + Send + Sync + 'static,
+    {
+        let flush_span = tracing::info_span!(
+            "batch_flush",
+            batch_size = batch.len(),
+            closed_by,
+            upstream_status = tracing::field::Empty,
+            duration_ms = tracing::field::Empty,
+        );
+        let flush_starting = Instant::now();
+        for message in &batch {
+            flush_span.follows_from(&message.caller_span);
+            // This message's queue wait ends here: it has been sitting in the
+            // channel and/or this batch since `try_submit`, and from this
+            // point on its latency is the upstream call timed below as
+            // `duration_ms`, not time spent queued.
+            let queue_wait_ms = (flush_starting - message.queued_at).as_millis() as u64;
+            message.caller_span.record("queue_wait_ms", queue_wait_ms);
+        }
+        async move {
+            let started = Instant::now();
+            let (payloads, replies): (Vec<Req>, Vec<_>) =
+                batch.into_iter().map(|message| (message.payload, message.reply)).unzip();
+            match service.call(payloads).await {
+                Ok(results) => {
+                    for (reply, result) in replies.into_iter().zip(results) {
+                        let _ = reply.send(Ok(result));
+                    }
+                }
+                Err(err) => {
+                    for reply in replies {
+                        let _ = reply.send(Err(err.clone()));
+                    }
+                }
+            }
+            tracing::Span::current().record("duration_ms", started.elapsed().as_millis() as u64);
+        }
+        .instrument(flush_span)
+        .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Id, Record};
+    use tracing::subscriber::DefaultGuard;
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::{Layer, Registry};
+
+    use super::*;
+
+    /// A [`BatchService`] that echoes each input straight back, so tests can
+    /// exercise [`Batch`]'s flushing policy without any real upstream call.
+    struct Echo;
+
+    impl BatchService<u32> for Echo {
+        type Response = u32;
+        type Error = ();
+
+        fn call(&self, batch: Vec<u32>) -> BoxFuture<'_, Result<Vec<u32>, ()>> {
+            Box::pin(async move { Ok(batch) })
+        }
+    }
+
+    /// Like [`Echo`], but sleeps for `delay` before replying, so a test can
+    /// tell the time a flush's upstream call takes apart from time spent
+    /// queued before it.
+    struct SlowEcho {
+        delay: Duration,
+    }
+
+    impl BatchService<u32> for SlowEcho {
+        type Response = u32;
+        type Error = ();
+
+        fn call(&self, batch: Vec<u32>) -> BoxFuture<'_, Result<Vec<u32>, ()>> {
+            let delay = self.delay;
+            Box::pin(async move {
+                tokio::time::sleep(delay).await;
+                Ok(batch)
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn flush_triggers_on_max_items_and_preserves_order() {
+        let (batch, _worker) = Batch::new(Echo, 3, Duration::from_secs(60));
+        let pendings: Vec<_> = (0..3u32).map(|i| batch.try_submit(i).unwrap()).collect();
+
+        let mut results = Vec::new();
+        for pending in pendings {
+            results.push(pending.wait().await.unwrap());
+        }
+        // The three sub-requests landed in one size-triggered batch; the
+        // response order must still line up with submission order.
+        assert_eq!(results, vec![0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn flush_triggers_on_max_latency_with_partial_batch() {
+        let (batch, _worker) = Batch::new(Echo, 10, Duration::from_millis(30));
+        let a = batch.try_submit(1).unwrap();
+        let b = batch.try_submit(2).unwrap();
+
+        // Only 2 of the 10 max_items arrived, so this can only resolve if
+        // the batch flushed on the max_latency timeout; bound the wait so a
+        // regression that drops the timeout hangs the test instead of the
+        // whole suite.
+        let results = tokio::time::timeout(Duration::from_millis(500), async {
+            (a.wait().await.unwrap(), b.wait().await.unwrap())
+        })
+        .await
+        .expect("partial batch should flush once max_latency elapses");
+        assert_eq!(results, (1, 2));
+    }
+
+    #[tokio::test]
+    async fn flush_timer_starts_at_first_item_arrival_not_loop_top() {
+        let (batch, _worker) = Batch::new(Echo, 10, Duration::from_millis(50));
+
+        // Idle for longer than max_latency before anything is submitted: if
+        // the flush timer started at the top of the worker's `loop` (the bug
+        // this refactor fixed) rather than when the first item of a batch
+        // actually arrives, this idle time would already have exhausted it
+        // and the batch below would flush immediately instead of waiting out
+        // a fresh max_latency.
+        tokio::time::sleep(Duration::from_millis(80)).await;
+
+        let submitted_at = Instant::now();
+        let pending = batch.try_submit(1).unwrap();
+        let result = tokio::time::timeout(Duration::from_millis(500), pending.wait())
+            .await
+            .expect("batch should still flush on its own max_latency after the first item arrives")
+            .unwrap();
+        assert_eq!(result, 1);
+        assert!(
+            submitted_at.elapsed() >= Duration::from_millis(40),
+            "flushed after {:?}, well before max_latency had elapsed since the first item arrived",
+            submitted_at.elapsed(),
+        );
+    }
+
+    #[tokio::test]
+    async fn try_submit_returns_full_once_queue_saturated() {
+        // `max_items` is never reached and `max_latency` never elapses
+        // within this test, but what actually keeps the queue from draining
+        // is that nothing here ever yields to the executor, so the worker
+        // task (spawned but not yet polled) can't dequeue anything.
+        let (batch, _worker) = Batch::new(Echo, usize::MAX, Duration::from_secs(60));
+
+        for i in 0..QUEUE_CAPACITY {
+            batch.try_submit(i as u32).expect("queue should have room up to its capacity");
+        }
+        assert!(matches!(batch.try_submit(QUEUE_CAPACITY as u32), Err(SubmitError::Full)));
+    }
+
+    #[tokio::test]
+    async fn drain_in_flight_batch_on_drop() {
+        // With `max_items` never reached and a long `max_latency`, the only
+        // way this batch ever flushes is the worker noticing the queue
+        // closed, exactly the path graceful shutdown relies on.
+        let (batch, worker) = Batch::new(Echo, 10, Duration::from_secs(60));
+        let pending = batch.try_submit(7).unwrap();
+
+        drop(batch);
+        worker.await.expect("worker task should not panic");
+
+        let result = tokio::time::timeout(Duration::from_millis(200), pending.wait())
+            .await
+            .expect("dropping the last handle should flush the partial batch and answer pending callers")
+            .unwrap();
+        assert_eq!(result, 7);
+    }
+
+    /// Captures the `queue_wait_ms` field recorded on whichever span is
+    /// current when [`Batch::try_submit`] is called, standing in for a real
+    /// tracing backend so the test can assert on the value `Batch::flush`
+    /// records without pulling one in. Delegates span bookkeeping (current
+    /// span tracking, storage) to [`Registry`], which the real binary relies
+    /// on via `tracing_subscriber::fmt` too.
+    #[derive(Clone, Default)]
+    struct QueueWaitCapture(Arc<Mutex<Option<u64>>>);
+
+    impl QueueWaitCapture {
+        fn install(&self) -> DefaultGuard {
+            tracing::subscriber::set_default(Registry::default().with(self.clone()))
+        }
+
+        fn recorded_ms(&self) -> Option<u64> {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    impl Visit for QueueWaitCapture {
+        fn record_u64(&mut self, field: &Field, value: u64) {
+            if field.name() == "queue_wait_ms" {
+                *self.0.lock().unwrap() = Some(value);
+            }
+        }
+
+        fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+    }
+
+    impl<S: tracing::Subscriber> Layer<S> for QueueWaitCapture {
+        fn on_record(&self, _span: &Id, values: &Record<'_>, _ctx: Context<'_, S>) {
+            values.record(&mut self.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn queue_wait_ms_excludes_upstream_latency() {
+        let capture = QueueWaitCapture::default();
+        let _subscriber_guard = capture.install();
+
+        // Flushes only by timeout, so the message's queue wait is governed
+        // by `max_latency`; the upstream call is made deliberately much
+        // slower so a regression back to measuring total handler latency
+        // (the bug fixed alongside this field's introduction) would show up
+        // as a `queue_wait_ms` far bigger than `max_latency`.
+        let max_latency = Duration::from_millis(30);
+        let upstream_delay = Duration::from_millis(200);
+        let (batch, _worker) = Batch::new(SlowEcho { delay: upstream_delay }, 10, max_latency);
+
+        let request_span = tracing::info_span!("test_request", queue_wait_ms = tracing::field::Empty);
+        let _span_guard = request_span.enter();
+
+        batch.call(1).await.unwrap();
+
+        let queue_wait_ms = capture.recorded_ms().expect("queue_wait_ms should have been recorded");
+        assert!(
+            queue_wait_ms < upstream_delay.as_millis() as u64,
+            "queue_wait_ms ({queue_wait_ms}ms) should exclude the {upstream_delay:?} upstream call",
+        );
+    }
+}
@@ -7,10 +7,10 @@
 //! ## Overview
 //!
 //! - **Incoming requests** are posted to `/embed` with a JSON body containing an `"inputs"` field.
-//! - Each request is turned into a [`Job`] and queued in an `mpsc` channel.
-//! - A background [`handle_batch`] task collects jobs into batches until either:
+//! - Each request is submitted to a [`batch::Batch`] worker wrapping an [`EmbeddingService`].
+//! - The worker collects requests into batches until either:
 //!   - [`MAX_BATCH_SIZE`] is reached, or
-//!   - [`MAX_WAIT_TIME_MILLIS`] milliseconds have passed since the first job in the batch.
+//!   - [`MAX_WAIT_TIME_MILLIS`] milliseconds have passed since the first request in the batch.
 //! - The batch is sent as a single request to the upstream Hugging Face inference service at
 //!   [`TARGET_SERVICE_URL`].
 //! - The upstream response is split back into individual responses in the same order,
@@ -18,18 +18,39 @@
 //!
 //! ## API
 //!
-//! **Request**:
+//! **Request**: `"inputs"` may be a single string, or an array of strings to submit
+//! many inputs in one call.
 //! ```json
 //! { "inputs": "some text" }
+//! { "inputs": ["some text", "some other text"] }
 //! ```
 //!
 //! **Response** (example):
 //! ```json
-//! {
-//!   "embedding": [0.0123, -0.0456, 0.0789]
-//! }
+//! { "embedding": [0.0123, -0.0456, 0.0789] }
+//! { "embeddings": [[0.0123, -0.0456, 0.0789], [0.0111, -0.0222, 0.0333]] }
 //! ```
 //!
+//! Array inputs are split into individual sub-requests that may land in different
+//! internal batches, but the response array always preserves the original input
+//! order. If an individual sub-request fails, its slot in the array carries an
+//! error marker rather than failing the whole request.
+//!
+//! If the internal job queue is saturated, a single-input request is rejected
+//! immediately with `503 Service Unavailable` (and a `Retry-After` header)
+//! rather than waiting for room, so load spikes turn into explicit
+//! backpressure instead of invisible latency. For an array input, each
+//! sub-request that finds the queue full gets an error marker in its slot
+//! instead, and the response is still `200 OK`.
+//!
+//! ## Shutdown
+//!
+//! On SIGINT/SIGTERM, Actix stops accepting new connections and waits for
+//! in-flight requests to finish, for at most its default shutdown timeout of
+//! 30 seconds; a request still unanswered after that is dropped. Only once
+//! that's done does `main` close the job queue and wait for the batching
+//! worker to flush any partial batch it's holding and deliver its replies.
+//!
 //! The upstream server may return either:
 //! - An array of float arrays (batch mode).
 //! - An object with a `"data"` key containing the array of float arrays.
@@ -38,7 +59,7 @@
 //!
 //! ## Constants
 //!
-//! - [`MAX_BATCH_SIZE`]: Maximum number of jobs in one batch.
+//! - [`MAX_BATCH_SIZE`]: Maximum number of requests in one batch.
 //! - [`MAX_WAIT_TIME_MILLIS`]: Maximum time to wait for batch filling.
 //! - [`TARGET_SERVICE_URL`]: URL of the upstream embedding service.
 //!
@@ -52,150 +73,220 @@
 //!
 //! ## Logging
 //!
-//! This service uses [`tracing`] for debug logs. Logs include batch size, timeouts, and
-//! errors from the upstream service.
+//! Each request is handled under a `handle_query` span carrying a unique
+//! `request_id` and the time it spent waiting on its batch (`queue_wait_ms`).
+//! Each batch flush runs under its own `batch_flush` span recording the batch
+//! size, whether it closed by size or by timeout, the upstream HTTP status,
+//! and the round-trip duration. Each flush span [`follows_from`](tracing::Span::follows_from)
+//! the span of every request that landed in it, so a single request can be
+//! traced from arrival through dispatch to response.
 //!
 //! ## Types
 //!
 //! - [`EmbedRequest`]: Incoming request payload.
 //! - [`EmbedResponse`]: Outgoing response payload.
-//! - [`Job`]: Internal struct containing the text to embed and a channel to send the embedding back.
+//! - [`EmbeddingService`]: The [`batch::BatchService`] that calls the upstream embedding server.
 //!
 //!
+mod batch;
 
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::{mpsc, oneshot};
 
-use actix_web::{web, App, HttpServer, Responder};
+use actix_web::{web, App, HttpResponse, HttpServer, Responder};
 use serde::{Deserialize, Serialize};
 
+use batch::{Batch, BatchError, BatchService, BoxFuture, CallError, SubmitError};
+
+/// `Retry-After` value (in seconds) sent alongside a 503 when the job queue
+/// is saturated.
+const RETRY_AFTER_SECS: u64 = 1;
+
+/// Source of the `request_id` recorded on each [`handle_query`] span, so a
+/// request can be picked out of the logs and followed through to the batch
+/// flush span it ends up in.
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
 const MAX_BATCH_SIZE: usize = 32; // maximum the server accepts
-const MAX_WAIT_TIME_MILLIS: u64= 10_000; //10 sec
+const MAX_WAIT_TIME_MILLIS: u64 = 10_000; //10 sec
 const TARGET_SERVICE_URL: &str = "http://127.0.0.1:8080/embed";
 
+/// `"inputs"` accepts either a single string or an array of strings, letting a
+/// client that already has a list of documents submit them in one call.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum EmbedInputs {
+    Single(String),
+    Batch(Vec<String>),
+}
 
 #[derive(Debug, Deserialize)]
 struct EmbedRequest {
-    inputs: String,
+    inputs: EmbedInputs,
 }
 
+/// Mirrors the shape of [`EmbedInputs`]: a single embedding for a single input,
+/// or an order-preserving array of embeddings for a batch of inputs.
 #[derive(Debug, Serialize)]
-struct EmbedResponse {
-    embedding: serde_json::Value,
+#[serde(untagged)]
+enum EmbedResponse {
+    Single { embedding: serde_json::Value },
+    Batch { embeddings: Vec<serde_json::Value> },
 }
 
+type EmbeddingBatch = Batch<String, serde_json::Value, ServiceError>;
 
-#[derive(Debug)]
-struct Job {
-    input: String,
-    inner_sender: oneshot::Sender<serde_json::Value>,
+/// Wraps the `reqwest` error that failed an upstream call in an `Arc`, so the
+/// same failure can be cheaply cloned and fanned out to every request in the
+/// batch that failed, while preserving the original error's `Display` text.
+#[derive(Debug, Clone)]
+struct ServiceError(Arc<reqwest::Error>);
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "upstream embedding request failed: {}", self.0)
+    }
+}
+
+impl From<reqwest::Error> for ServiceError {
+    fn from(err: reqwest::Error) -> Self {
+        ServiceError(Arc::new(err))
+    }
 }
 
+/// A [`BatchService`] that forwards a batch of inputs to the upstream
+/// `text-embeddings-inference` server in a single HTTP request.
+struct EmbeddingService {
+    client: reqwest::Client,
+}
+
+impl BatchService<String> for EmbeddingService {
+    type Response = serde_json::Value;
+    type Error = ServiceError;
+
+    fn call(&self, batch: Vec<String>) -> BoxFuture<'_, Result<Vec<Self::Response>, Self::Error>> {
+        Box::pin(async move {
+            let response = self
+                .client
+                .post(TARGET_SERVICE_URL)
+                .json(&serde_json::json!({"inputs": batch}))
+                .send()
+                .await?;
+            tracing::Span::current().record("upstream_status", response.status().as_u16());
+            let val: serde_json::Value = response.json().await?;
+            let results = if let Some(data) = val.get("data").and_then(|d| d.as_array()) {
+                data.clone()
+            } else if let Some(arr) = val.as_array() {
+                arr.clone()
+            } else {
+                vec![]
+            };
+            Ok(results)
+        })
+    }
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-
     tracing_subscriber::fmt()
         .with_max_level(tracing::Level::DEBUG)
         .init();
 
+    let service = EmbeddingService {
+        client: reqwest::Client::new(), // better create the client before the batch starts.
+    };
+    let (batch, worker) = EmbeddingBatch::new(service, MAX_BATCH_SIZE, Duration::from_millis(MAX_WAIT_TIME_MILLIS));
 
-    let (job_sender, job_receiver) = mpsc::channel::<Job>(1024);
-    tokio::spawn(handle_batch(job_receiver));
-
-    HttpServer::new(move || {
+    // Actix installs SIGINT/SIGTERM handlers by default and stops the
+    // acceptor gracefully on either: new connections are refused and
+    // in-flight requests are given until `run()` resolves to finish. `run()`
+    // resolves once every `handle_query` call (and the oneshot::Receiver it's
+    // waiting on) has been answered, or once Actix's default shutdown timeout
+    // of 30 seconds has passed, in which case the calls still waiting are
+    // dropped without a response.
+    let server_batch = batch.clone();
+    let result = HttpServer::new(move || {
         tracing::debug!("Server started.");
         App::new()
-            .app_data(web::Data::new(job_sender.clone()))
+            .app_data(web::Data::new(server_batch.clone()))
             .route("/embed", web::post().to(handle_query))
         })
         .bind(("0.0.0.0", 3000))?
         .run()
-        .await
+        .await;
+
+    // Every request has been answered and the acceptor has shut down, so it's
+    // safe to close the job queue: drop our handle and wait for the worker to
+    // flush whatever partial batch it's still holding before we exit.
+    drop(batch);
+    let _ = worker.await;
+
+    result
 }
 
-async fn handle_query(sender: web::Data<mpsc::Sender<Job>>, payload: web::Json<EmbedRequest>) -> impl Responder {
-    let (inner_sender, inner_receiver) = oneshot::channel();
-    let job = Job {
-        input: payload.inputs.clone(),
-        inner_sender
-    };
-    if !sender.send(job).await.is_ok() {
-        return web::Json(EmbedResponse { embedding: serde_json::json!("Failed to batch.")});
-    }
-    match inner_receiver.await {
-        Ok(response) => {
-            web::Json(EmbedResponse {embedding: response})
+#[tracing::instrument(
+    name = "handle_query",
+    skip(batch, payload),
+    fields(request_id = tracing::field::Empty, queue_wait_ms = tracing::field::Empty)
+)]
+async fn handle_query(batch: web::Data<EmbeddingBatch>, payload: web::Json<EmbedRequest>) -> impl Responder {
+    let request_id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+    tracing::Span::current().record("request_id", request_id);
+
+    // `queue_wait_ms` is recorded directly onto this span from inside
+    // `batch::flush`, once each submitted message's queueing latency (not
+    // the batch's upstream round trip) is actually known.
+    match &payload.inputs {
+        EmbedInputs::Single(input) => match batch.call(input.clone()).await {
+            Ok(embedding) => HttpResponse::Ok().json(EmbedResponse::Single { embedding }),
+            Err(err) => error_response(err),
+        },
+        EmbedInputs::Batch(inputs) => {
+            // Submit every sub-request first so they can be picked up by
+            // whichever internal batches have room, then await the results in
+            // the original order so the response array lines up with the
+            // request regardless of which batch each sub-request actually
+            // landed in. A sub-request failure, including a saturated queue,
+            // only marks its own slot in the array; it does not fail the
+            // whole call.
+            let submissions: Vec<_> = inputs.iter().map(|input| batch.try_submit(input.clone())).collect();
+            let mut embeddings = Vec::with_capacity(submissions.len());
+            for submission in submissions {
+                let value = match submission {
+                    Ok(pending) => match pending.wait().await {
+                        Ok(embedding) => embedding,
+                        Err(BatchError::QueueClosed) => {
+                            serde_json::json!({"error": "batching worker is not running"})
+                        }
+                        Err(BatchError::Upstream(err)) => serde_json::json!({"error": err.to_string()}),
+                    },
+                    Err(SubmitError::Full) => serde_json::json!({"error": "service overloaded, retry later"}),
+                    Err(SubmitError::Closed) => serde_json::json!({"error": "batching worker is not running"}),
+                };
+                embeddings.push(value);
+            }
+            HttpResponse::Ok().json(EmbedResponse::Batch { embeddings })
         }
-        Err(_) => web::Json(EmbedResponse { embedding: serde_json::json!("Batching Failed. Try again.")}),
     }
 }
 
-async fn handle_batch(mut job_receiver: mpsc::Receiver<Job>) {
-    let client = reqwest::Client::new(); // better create the client before the batch
-                                                 // starts.
-    loop {
-        let mut batch: Vec<Job> = vec![];
-        let alarm_clock = tokio::time::sleep(Duration::from_millis(MAX_WAIT_TIME_MILLIS));
-        tokio::pin!(alarm_clock);
-        while batch.len() < MAX_BATCH_SIZE {
-            tokio::select! {
-                received = job_receiver.recv() => {
-                    match received {
-                        Some(job) => {
-                            batch.push(job);
-                            if batch.len() > MAX_BATCH_SIZE {
-                            tracing::debug!("Batch Maxed out");
-                                break;
-                            }
-                        },
-                        None => break,
-                    }
-                }
-                _ =  &mut alarm_clock => {
-                    tracing::debug!("Batch Timed out");
-                    break; 
-                }
-            }
+/// Translates a failure to complete a request into the matching HTTP status:
+/// a saturated queue is explicit backpressure (503, with `Retry-After`), a
+/// closed batching worker is a server-side fault (500), and an upstream
+/// failure means the request made it through but the embedding server
+/// couldn't service it (502).
+fn error_response(err: CallError<ServiceError>) -> HttpResponse {
+    match err {
+        CallError::Full => HttpResponse::ServiceUnavailable()
+            .insert_header(("Retry-After", RETRY_AFTER_SECS.to_string()))
+            .json(serde_json::json!({"error": "service overloaded, retry later"})),
+        CallError::Closed => {
+            HttpResponse::InternalServerError().json(serde_json::json!({"error": "batching worker is not running"}))
         }
-        let copies_to_send: Vec<String> = batch.iter().map(|x| x.input.clone()).collect();
-        match client.post(TARGET_SERVICE_URL) 
-            .json(&serde_json::json!({"inputs": copies_to_send}))
-            .send()
-            .await {
-                Ok(batch_response) => {
-                    let json: Result<serde_json::Value, reqwest::Error> = batch_response.json().await; 
-                    match json {
-                        Ok(val) => {
-                            let results = if let Some(data) = val.get("data").and_then(|d| d.as_array()) {
-                                data.clone()
-                            } else if let Some(arr) = val.as_array() {
-                                arr.clone()
-                            } else {
-                                vec![]
-                            };
-                            for (job, result) in batch.into_iter().zip(results.into_iter()) {
-                                let _ = job.inner_sender.send(result);
-                            }
-                        },
-                        Err(_) => {
-                            batch
-                                .into_iter()
-                                .for_each(|job| {
-                                    _ = job.inner_sender.send(serde_json::json!({"error": "upstream failed"}));
-                                });
-                        }
-                    }
-                    },
-                Err(_) => {
-
-                    batch
-                        .into_iter()
-                        .for_each(|job| {
-                            _ = job.inner_sender.send(serde_json::json!({"error": "upstream failed"}));
-                        });
-
-                }
+        CallError::Upstream(err) => {
+            HttpResponse::BadGateway().json(serde_json::json!({"error": err.to_string()}))
         }
     }
 }